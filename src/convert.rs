@@ -0,0 +1,308 @@
+//! Conversion between ruf-compressed files and the standard `tar` and `zip` formats.
+//!
+//! A ruf file holds the content of a single file and no metadata, so:
+//! - converting to `tar`/`zip` writes one member, named after the ruf file without its extension
+//!   and stamped with the ruf file's modification time;
+//! - converting from `tar`/`zip` requires the archive to contain exactly one regular file,
+//!   whose content is compressed and whose metadata is dropped.
+//!
+//! The archives are read and written with the `tar` and `zip` crates. Archive members are
+//! streamed: the decoded content of a ruf file is piped into the archive as it is restored,
+//! and the member of an archive is read straight from the source file.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, PipeReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+    thread,
+    time::UNIX_EPOCH,
+};
+
+use clap::ValueEnum;
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter, write::SimpleFileOptions};
+
+use crate::core::*;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    Ruf,
+    Tar,
+    Zip,
+}
+
+/// Convert `src` into `dest` in the `to` format.
+///
+/// `src_path` is used to name the member when converting to `tar` or `zip`.
+pub fn convert_file(src: &File, src_path: &Path, to: Format, dest: &mut File) -> io::Result<()> {
+    let mut writer = BufWriter::new(dest);
+    match to {
+        Format::Ruf => archive_to_ruf(BufReader::new(src), &mut writer)?,
+        Format::Tar | Format::Zip => {
+            // the decoder works on a slice, so the (compressed) ruf data is read whole
+            let mut buf = Vec::new();
+            BufReader::new(src).read_to_end(&mut buf)?;
+
+            let name = member_name(src_path)?;
+            let mtime = src
+                .metadata()?
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            if let Format::Tar = to {
+                ruf_to_tar(&buf, &name, mtime, &mut writer)?;
+            } else {
+                ruf_to_zip(&buf, &name, mtime, &mut writer)?;
+            }
+        }
+    }
+    writer.flush()
+}
+
+fn member_name(src_path: &Path) -> io::Result<String> {
+    src_path
+        .with_extension("")
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map(String::from)
+        .ok_or_else(|| invalid("cannot derive a member name from the source path"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Decode the ruf data in `buf` on another thread, and hand `consume` a pipe to read the content from.
+///
+/// Only a pipe buffer of the content is in memory at a time.
+fn with_extracted<R>(buf: &[u8], consume: impl FnOnce(&mut PipeReader) -> io::Result<R>) -> io::Result<R> {
+    let (mut reader, mut writer) = io::pipe()?;
+    thread::scope(|s| {
+        // the writing end is dropped when the thread is done, which ends the content
+        let decoder = s.spawn(move || extract_into(buf, &mut writer));
+        let res = consume(&mut reader);
+        // a decoder blocked on a full pipe gets an error instead of waiting forever
+        drop(reader);
+        let decoded = decoder.join().expect("the decoder thread panicked");
+        // an error of `consume` makes the decoder fail as well, so it is the one to report
+        res.and_then(|r| decoded.map(|_| r))
+    })
+}
+
+/// Decode the ruf data in `buf` into `out` chunk by chunk.
+fn extract_into<W: Write>(buf: &[u8], out: &mut W) -> io::Result<()> {
+    const CHUNK_SIZE: usize = 64 * 1024;
+    let mut chunk: Vec<u8> = Vec::with_capacity(CHUNK_SIZE);
+
+    // a failed write stops the decoding, instead of piling up the rest of the content
+    try_extract_each::<u8, io::Error, _>(buf, |byte| {
+        chunk.push(byte);
+        if chunk.len() == CHUNK_SIZE {
+            out.write_all(&chunk)?;
+            chunk.clear();
+        }
+        Ok(())
+    })?;
+
+    out.write_all(&chunk)
+}
+
+fn archive_to_ruf<R: Read + Seek, W: Write>(mut src: R, out: &mut W) -> io::Result<()> {
+    let mut head = Vec::with_capacity(TAR_MAGIC_END);
+    (&mut src).take(TAR_MAGIC_END as u64).read_to_end(&mut head)?;
+    src.seek(SeekFrom::Start(0))?;
+
+    let content = if head.starts_with(b"PK\x03\x04") {
+        read_single_zip_member(src)?
+    } else if head.len() == TAR_MAGIC_END && &head[257..] == b"ustar" {
+        read_single_tar_member(src)?
+    } else {
+        return Err(invalid("source is neither a tar nor a zip archive"));
+    };
+    out.write_all(&compress_bytes(&content))
+}
+
+// ---------- tar ----------
+
+/// The `ustar` magic of a tar header ends at this offset.
+const TAR_MAGIC_END: usize = 262;
+
+fn ruf_to_tar<W: Write>(buf: &[u8], name: &str, mtime: u64, out: &mut W) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(extracted_len::<u8>(buf)?);
+    header.set_mtime(mtime);
+    header.set_mode(0o644);
+    header.set_entry_type(tar::EntryType::Regular);
+
+    let mut builder = tar::Builder::new(out);
+    with_extracted(buf, |content| builder.append_data(&mut header, name, content))?;
+    builder.finish()
+}
+
+fn read_single_tar_member<R: Read>(src: R) -> io::Result<Vec<u8>> {
+    let mut archive = tar::Archive::new(src);
+    let mut content = None;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        // everything but regular files (directories, links, ...) is skipped
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        if content.is_some() {
+            return Err(invalid("tar archive holds more than one file, but a ruf file holds one"));
+        }
+        let mut buf = Vec::new();
+        entry.read_to_end(&mut buf)?;
+        content = Some(buf);
+    }
+
+    content.ok_or_else(|| invalid("tar archive holds no regular file"))
+}
+
+// ---------- zip ----------
+
+fn ruf_to_zip<W: Write>(buf: &[u8], name: &str, mtime: u64, out: &mut W) -> io::Result<()> {
+    let size = extracted_len::<u8>(buf)?;
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .last_modified_time(zip_date_time(mtime))
+        // zip64 records are needed from 4 GiB on
+        .large_file(size >= u32::MAX as u64);
+
+    // `dest` may be a pipe, so the archive is written front to back without seeking
+    let mut zip = ZipWriter::new_stream(out);
+    zip.start_file(name, options)?;
+    with_extracted(buf, |content| io::copy(content, &mut zip))?;
+    zip.finish()?;
+    Ok(())
+}
+
+fn read_single_zip_member<R: Read + Seek>(src: R) -> io::Result<Vec<u8>> {
+    let mut archive = ZipArchive::new(src)?;
+    let mut member = None;
+
+    for i in 0..archive.len() {
+        // directories are skipped
+        if archive.by_index_raw(i)?.is_file() {
+            if member.is_some() {
+                return Err(invalid("zip archive holds more than one file, but a ruf file holds one"));
+            }
+            member = Some(i);
+        }
+    }
+
+    let i = member.ok_or_else(|| invalid("zip archive holds no file"))?;
+    let mut content = Vec::new();
+    archive.by_index(i)?.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// The zip timestamp of a unix timestamp, in UTC.
+/// Times zip cannot express (before 1980 or after 2107) become 1980-01-01.
+fn zip_date_time(unix_secs: u64) -> DateTime {
+    let days = (unix_secs / 86400) as i64;
+    let secs = unix_secs % 86400;
+
+    // civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let Ok(year) = u16::try_from(year) else {
+        return DateTime::default();
+    };
+    DateTime::from_date_and_time(
+        year,
+        month as u8,
+        day as u8,
+        (secs / 3600) as u8,
+        (secs % 3600 / 60) as u8,
+        (secs % 60) as u8,
+    )
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn hello_ruf() -> Vec<u8> {
+        compress_bytes(b"Hello, world!\n")
+    }
+
+    #[test]
+    fn test_zip_date_time() {
+        // 2024-02-29 13:45:30 UTC
+        let dt = zip_date_time(1709214330);
+        assert_eq!(
+            (dt.year(), dt.month(), dt.day(), dt.hour(), dt.minute(), dt.second()),
+            (2024, 2, 29, 13, 45, 30)
+        );
+        assert_eq!(zip_date_time(0), DateTime::default());
+    }
+
+    #[test]
+    fn test_tar_round_trip() {
+        let mut tar = Vec::new();
+        ruf_to_tar(&hello_ruf(), "hello.txt", 0, &mut tar).unwrap();
+        assert_eq!(tar.len() % 512, 0);
+        assert_eq!(read_single_tar_member(Cursor::new(&tar)).unwrap(), b"Hello, world!\n");
+
+        let mut ruf = Vec::new();
+        archive_to_ruf(Cursor::new(&tar), &mut ruf).unwrap();
+        assert_eq!(extract::<u8>(&ruf).unwrap(), b"Hello, world!\n");
+    }
+
+    #[test]
+    fn test_zip_round_trip() {
+        let mut zip = Vec::new();
+        ruf_to_zip(&hello_ruf(), "hello.txt", 1709214330, &mut zip).unwrap();
+        assert_eq!(read_single_zip_member(Cursor::new(&zip)).unwrap(), b"Hello, world!\n");
+
+        let mut ruf = Vec::new();
+        archive_to_ruf(Cursor::new(&zip), &mut ruf).unwrap();
+        assert_eq!(extract::<u8>(&ruf).unwrap(), b"Hello, world!\n");
+    }
+
+    #[test]
+    fn test_corrupted_ruf_fails() {
+        let mut ruf = hello_ruf();
+        let len = ruf.len();
+        ruf.truncate(len - 30);
+        assert!(ruf_to_tar(&ruf, "hello.txt", 0, &mut Vec::new()).is_err());
+    }
+
+    /// A writer for a full disk, counting how often it was tried.
+    struct FailingWriter(usize);
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            self.0 += 1;
+            Err(io::Error::other("no space left"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_failing_writer() {
+        let ruf = compress_bytes(&vec![b'x'; 1 << 20]);
+
+        // the first failed write ends the decoding
+        let mut out = FailingWriter(0);
+        assert!(extract_into(&ruf, &mut out).is_err());
+        assert_eq!(out.0, 1);
+
+        assert!(ruf_to_tar(&ruf, "x.txt", 0, &mut FailingWriter(0)).is_err());
+        assert!(ruf_to_zip(&ruf, "x.txt", 0, &mut FailingWriter(0)).is_err());
+    }
+}
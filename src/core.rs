@@ -1,10 +1,14 @@
 /// This module contains the core logic of compression and extraction.
 use crate::huffman::*;
+use crate::stats::PhaseTimer;
 use bitvec::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use rayon::prelude::*;
+use std::borrow::Borrow;
 use std::collections::BTreeMap;
 use std::hash::Hash;
+use std::fmt;
+use std::io::{self, Read};
 
 #[derive(Serialize, Deserialize)]
 struct CompressedData<T: Ord + Hash> {
@@ -18,12 +22,77 @@ struct CompressedData<T: Ord + Hash> {
 
 /// Compress a File into a Vec<u8>
 /// you can decide what token you want to use
-pub fn compress<T>(tokens: &Vec<T>) -> Vec<u8>
+#[allow(unused)]
+pub fn compress<T>(tokens: &[T]) -> Vec<u8>
 where
-    T: Clone + Ord + Hash + Serialize + Send + Sync,
+    T: Ord + Hash + Serialize + Sync,
 {
-    // generate frequency table
+    compress_timed(tokens, &mut PhaseTimer::new())
+}
+
+/// Same as `compress`, recording the time of each phase into `timer`.
+///
+/// Tokens are never cloned: the frequency table, the tree and the coding table
+/// all hold references into `tokens`, and a `&T` serializes just like a `T`.
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all, fields(tokens = tokens.len())))]
+pub fn compress_timed<T>(tokens: &[T], timer: &mut PhaseTimer) -> Vec<u8>
+where
+    T: Ord + Hash + Serialize + Sync,
+{
+    let frequency_table = timer.time("counting", || count_frequencies(tokens));
+
+    // build huffman tree
+    // empty input has no tree, and no token to code either
+    let code_table = timer.time("tree building", || {
+        build_huffman_tree(&frequency_table)
+            .map(|tree| get_coding_table(&tree))
+            .unwrap_or_default()
+    });
+
+    let data = timer.time("bit packing", || pack_tokens(tokens, &code_table));
+
+    timer.time("serialization", || serialize(frequency_table, data))
+}
+
+/// Compress bytes, the same as `compress` does with `u8` tokens.
+///
+/// Looking codes up in a flat table indexed by the byte is much cheaper than
+/// in the `BTreeMap` of the generic path, and bit packing is the hottest loop of compression.
+pub fn compress_bytes(tokens: &[u8]) -> Vec<u8> {
+    compress_bytes_timed(tokens, &mut PhaseTimer::new())
+}
 
+/// Same as `compress_bytes`, recording the time of each phase into `timer`.
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all, fields(tokens = tokens.len())))]
+pub fn compress_bytes_timed(tokens: &[u8], timer: &mut PhaseTimer) -> Vec<u8> {
+    // copying at most 256 bytes out of the references is cheaper than handling `&u8` everywhere
+    let frequency_table: BTreeMap<u8, u64> = timer.time("counting", || {
+        count_frequencies(tokens)
+            .into_iter()
+            .map(|(token, f)| (*token, f))
+            .collect()
+    });
+
+    let Some(tree) = timer.time("tree building", || build_huffman_tree(&frequency_table)) else {
+        // empty input has no tree, and no bits
+        return timer.time("serialization", || serialize(frequency_table, BitVec::new()));
+    };
+    let byte_table = timer.time("tree building", || get_byte_coding_table(&tree));
+
+    let data = timer.time("bit packing", || match byte_table {
+        Some(byte_table) => pack_bytes(tokens, &byte_table),
+        // some code is too long to be packed, take the generic path
+        None => pack_tokens(tokens, &get_coding_table(&tree)),
+    });
+
+    timer.time("serialization", || serialize(frequency_table, data))
+}
+
+// generate frequency table, counting tokens by reference
+fn count_frequencies<T>(tokens: &[T]) -> BTreeMap<&T, u64>
+where
+    T: Ord + Sync,
+{
     // let mut frequency_table: BTreeMap<T, u64> = BTreeMap::new();
     // for token in tokens {
     //     let freq = frequency_table.get_mut(&token);
@@ -39,86 +108,375 @@ where
 
     // this piece of code make use of `rayon` crate for parallelism
     // to coping with par_iter, use functional programming style.
-    let frequency_table = tokens.par_iter()
-            .fold(|| BTreeMap::new(), |mut map: BTreeMap<T, u64>, token: &T| {
-                *map.entry(token.clone()).or_insert(0) += 1;
-                map
-            })
-            .reduce(|| BTreeMap::new(), |mut map1, map2| {
-                map2.into_iter()
-                    .for_each(|(t, f)| {
-                        *map1.entry(t).or_insert(0) += f;
-                    });
-                map1
-            });
-
-    // build huffman tree
-    let tree = build_huffman_tree(&frequency_table).unwrap();
-    let code_table = get_coding_table(&tree);
-
-    // generate compressed data
+    tokens.par_iter()
+        .fold(BTreeMap::new, |mut map: BTreeMap<&T, u64>, token: &T| {
+            *map.entry(token).or_insert(0) += 1;
+            map
+        })
+        .reduce(BTreeMap::new, |mut map1, map2| {
+            map2.into_iter()
+                .for_each(|(t, f)| {
+                    *map1.entry(t).or_insert(0) += f;
+                });
+            map1
+        })
+}
 
+// generate compressed data
+// the keys of `code_table` may be either tokens or references to them
+fn pack_tokens<T, K>(tokens: &[T], code_table: &BTreeMap<K, BitVec<u8, Msb0>>) -> BitVec<u8, Msb0>
+where
+    T: Ord + Sync,
+    K: Borrow<T> + Ord + Sync,
+{
     // let mut data = bitvec![u8, Msb0;];
     // for token in tokens {
     //     let token_code = code_table.get(&token).unwrap();
     //     data.extend(token_code);
     // }
 
-    let data = tokens.par_iter()
-            .fold(|| bitvec![u8, Msb0;], |mut bv, token| {
-                let token_code = code_table.get(token).unwrap();
-                bv.extend(token_code);
-                bv    
-            })
-            .reduce(|| bitvec![u8, Msb0;], |mut bv1, bv2| {
-                bv1.extend(bv2);
-                bv1
-            });
+    tokens.par_iter()
+        .fold(|| bitvec![u8, Msb0;], |mut bv, token| {
+            let token_code = code_table.get(token).unwrap();
+            bv.extend(token_code);
+            bv    
+        })
+        .reduce(|| bitvec![u8, Msb0;], |mut bv1, bv2| {
+            bv1.extend(bv2);
+            bv1
+        })
+}
+
+/// Pack bytes with a flat code table.
+///
+/// Each parallel chunk shifts codes into an integer accumulator and flushes whole bytes,
+/// so only the few bits left over at the end of a chunk go through `BitVec`.
+fn pack_bytes(tokens: &[u8], byte_table: &[PackedCode; 256]) -> BitVec<u8, Msb0> {
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    tokens.par_chunks(CHUNK_SIZE)
+        .map(|chunk| {
+            let mut bytes = Vec::with_capacity(chunk.len());
+            // at most 7 pending bits plus a 64-bit code
+            let mut acc: u128 = 0;
+            let mut acc_len: u32 = 0;
+            for &token in chunk {
+                let code = byte_table[token as usize];
+                acc = (acc << code.len) | code.bits as u128;
+                acc_len += code.len as u32;
+                while acc_len >= 8 {
+                    acc_len -= 8;
+                    bytes.push((acc >> acc_len) as u8);
+                }
+                acc &= (1 << acc_len) - 1;
+            }
+
+            let mut bv = BitVec::<u8, Msb0>::from_vec(bytes);
+            for i in (0..acc_len).rev() {
+                bv.push((acc >> i) & 1 == 1);
+            }
+            bv
+        })
+        .reduce(|| bitvec![u8, Msb0;], |mut bv1, bv2| {
+            bv1.extend_from_bitslice(&bv2);
+            bv1
+        })
+}
+
+// Every compressed buffer ends with a fixed-size footer:
+//     body length (u64 LE) | token count (u64 LE) | FOOTER_MAGIC
+// so that a buffer which was cut short, or had bytes lost in the middle,
+// is reported instead of decoding into a silently shortened output.
+const FOOTER_MAGIC: &[u8; 4] = b"RUF\x01";
+const FOOTER_LEN: usize = 8 + 8 + FOOTER_MAGIC.len();
 
+fn serialize<T>(frequency_table: BTreeMap<T, u64>, data: BitVec<u8, Msb0>) -> Vec<u8>
+where
+    T: Ord + Hash + Serialize,
+{
     let len = data.len();
     let data = data.into_vec();
+    let token_count: u64 = frequency_table.values().sum();
     
     let compressed_data = CompressedData {
         encoder: frequency_table,
-        data: data,
+        data,
         bit_len: len,
     };
 
-    rmp_serde::to_vec(&compressed_data).unwrap()
+    let mut buf = rmp_serde::to_vec(&compressed_data).unwrap();
+    let body_len = buf.len() as u64;
+    buf.extend_from_slice(&body_len.to_le_bytes());
+    buf.extend_from_slice(&token_count.to_le_bytes());
+    buf.extend_from_slice(FOOTER_MAGIC);
+    buf
+}
+
+/// Why a buffer could not be extracted.
+#[derive(Debug)]
+pub enum ExtractError {
+    /// The buffer ends before all of its data.
+    Truncated,
+    /// The buffer is not valid compressed data.
+    Corrupted(String),
+    /// The compressed data could not be read.
+    Io(io::Error),
+}
+
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "the compressed data is truncated"),
+            Self::Corrupted(reason) => write!(f, "the compressed data is corrupted: {}", reason),
+            Self::Io(e) => write!(f, "cannot read the compressed data: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ExtractError {}
+
+impl From<rmp_serde::decode::Error> for ExtractError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        use rmp_serde::decode::Error::*;
+        match &e {
+            InvalidMarkerRead(io) | InvalidDataRead(io) if io.kind() == io::ErrorKind::UnexpectedEof => {
+                Self::Truncated
+            }
+            _ => Self::Corrupted(e.to_string()),
+        }
+    }
 }
 
-pub fn extract<'de, T>(buf: &'de Vec<u8>) -> Vec<T>
+impl From<ExtractError> for io::Error {
+    fn from(e: ExtractError) -> Self {
+        match e {
+            ExtractError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/// Split `buf` into the serialized body and the token count recorded in its footer.
+///
+/// Buffers written before the footer existed have none, and get `None` as token count.
+/// A buffer cut inside its footer looks the same, but its body is still complete.
+fn split_footer(buf: &[u8]) -> Result<(&[u8], Option<u64>), ExtractError> {
+    if buf.len() < FOOTER_LEN || !buf.ends_with(FOOTER_MAGIC) {
+        return Ok((buf, None));
+    }
+
+    let (body, footer) = buf.split_at(buf.len() - FOOTER_LEN);
+    let body_len = u64::from_le_bytes(footer[..8].try_into().unwrap());
+    let token_count = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+    if body_len > body.len() as u64 {
+        return Err(ExtractError::Truncated);
+    }
+    if body_len < body.len() as u64 {
+        return Err(ExtractError::Corrupted(String::from("unexpected data before the footer")));
+    }
+    Ok((body, Some(token_count)))
+}
+
+/// Deserialize the body of `buf`, along with the number of tokens it must decode to.
+fn deserialize<'de, T>(buf: &'de [u8]) -> Result<(CompressedData<T>, u64), ExtractError>
+where
+    T: Ord + Hash + Deserialize<'de>,
+{
+    let (body, token_count) = split_footer(buf)?;
+    let compressed_data: CompressedData<T> = rmp_serde::from_slice(body)?;
+
+    let total = compressed_data
+        .encoder
+        .values()
+        .try_fold(0u64, |total, &f| total.checked_add(f))
+        .ok_or_else(|| ExtractError::Corrupted(String::from("the frequency table overflows")))?;
+    match token_count {
+        Some(count) if count != total => Err(ExtractError::Corrupted(format!(
+            "the footer records {} tokens but the frequency table {}",
+            count, total
+        ))),
+        _ => Ok((compressed_data, total)),
+    }
+}
+
+/// Count the tokens that `extract` would restore from `buf`, without decoding the data.
+///
+/// The frequency table already records how often each token occurs,
+/// so the count is just the sum of it.
+pub fn extracted_len<'de, T>(buf: &'de [u8]) -> Result<u64, ExtractError>
+where
+    T: Clone + Ord + Hash + Deserialize<'de>,
+{
+    let (_, token_count) = deserialize::<T>(buf)?;
+    Ok(token_count)
+}
+
+#[allow(unused)]
+pub fn extract<'de, T>(buf: &'de [u8]) -> Result<Vec<T>, ExtractError>
+where
+    T: Clone + Ord + Hash + Deserialize<'de>,
+{
+    let mut tokens = Vec::new();
+    extract_each(buf, |token| tokens.push(token))?;
+    Ok(tokens)
+}
+
+/// Decode `buf` token by token, handing each one to `f` as soon as it is restored.
+///
+/// Unlike `extract`, the restored tokens are never collected,
+/// so callers can scan through the output without holding all of it in memory.
+/// If `buf` turns out to be truncated or corrupted, `f` may already have seen part of the output.
+pub fn extract_each<'de, T, F>(buf: &'de [u8], f: F) -> Result<(), ExtractError>
+where
+    T: Clone + Ord + Hash + Deserialize<'de>,
+    F: FnMut(T),
+{
+    extract_each_timed(buf, f, &mut PhaseTimer::new())
+}
+
+/// Same as `extract_each`, recording the time of each phase into `timer`.
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all, fields(bytes = buf.len())))]
+pub fn extract_each_timed<'de, T, F>(buf: &'de [u8], mut f: F, timer: &mut PhaseTimer) -> Result<(), ExtractError>
 where
     T: Clone + Ord + Hash + Deserialize<'de>,
+    F: FnMut(T),
 {
-    let compressed_data: CompressedData<T> = rmp_serde::from_slice(buf).unwrap();
+    let (compressed_data, token_count) = timer.time("deserialization", || deserialize::<T>(buf))?;
+    let mut f = |token| {
+        f(token);
+        Ok::<_, ExtractError>(())
+    };
+    restore(&compressed_data, token_count, &mut f, timer)
+}
 
+/// Same as `extract_each`, but `f` can fail, e.g. when writing the tokens somewhere.
+///
+/// Decoding stops at the first error of `f`, which is returned as is.
+pub fn try_extract_each<'de, T, E, F>(buf: &'de [u8], mut f: F) -> Result<(), E>
+where
+    T: Clone + Ord + Hash + Deserialize<'de>,
+    E: From<ExtractError>,
+    F: FnMut(T) -> Result<(), E>,
+{
+    let (compressed_data, token_count) = deserialize::<T>(buf)?;
+    restore(&compressed_data, token_count, &mut f, &mut PhaseTimer::new())
+}
+
+/// Same as `extract`, but the tokens are deserialized as owned values,
+/// so nothing returned borrows from `buf` and it can be dropped right away.
+///
+/// This is handy when `T` must be `DeserializeOwned`, e.g. behind a generic reader.
+#[allow(unused)]
+pub fn extract_owned<T>(buf: &[u8]) -> Result<Vec<T>, ExtractError>
+where
+    T: Clone + Ord + Hash + DeserializeOwned,
+{
+    let (compressed_data, token_count) = deserialize::<T>(buf)?;
+    let mut tokens = Vec::new();
+    let mut push = |token| {
+        tokens.push(token);
+        Ok::<_, ExtractError>(())
+    };
+    restore(&compressed_data, token_count, &mut push, &mut PhaseTimer::new())?;
+    Ok(tokens)
+}
+
+/// Same as `extract_owned`, reading the compressed data from `reader`
+/// instead of a buffer the caller has to keep around.
+///
+/// The footer is at the very end, so `reader` is read up to its end before decoding.
+#[allow(unused)]
+pub fn extract_from_reader<T, R>(mut reader: R) -> Result<Vec<T>, ExtractError>
+where
+    T: Clone + Ord + Hash + DeserializeOwned,
+    R: Read,
+{
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf).map_err(ExtractError::Io)?;
+    extract_owned(&buf)
+}
+
+fn restore<T, E, F>(
+    compressed_data: &CompressedData<T>,
+    token_count: u64,
+    f: &mut F,
+    timer: &mut PhaseTimer,
+) -> Result<(), E>
+where
+    T: Clone + Ord + Hash,
+    E: From<ExtractError>,
+    F: FnMut(T) -> Result<(), E>,
+{
     // restore the huffman tree from the coding table
-    let tree = build_huffman_tree(&compressed_data.encoder).unwrap();
+    let Some(tree) = timer.time("tree building", || build_huffman_tree(&compressed_data.encoder)) else {
+        // only empty input has an empty frequency table, and so `token_count` is 0 as well
+        if compressed_data.bit_len > 0 {
+            return Err(ExtractError::Corrupted(String::from("the frequency table is empty")).into());
+        }
+        return Ok(());
+    };
+
+    // restore original tokens by walking on the huffman tree
+    timer.time("decoding", || decode(compressed_data, &tree, token_count, f))
+}
+
+fn decode<T, E, F>(
+    compressed_data: &CompressedData<T>,
+    tree: &HuffmanTree<T>,
+    token_count: u64,
+    f: &mut F,
+) -> Result<(), E>
+where
+    T: Clone + Ord + Hash,
+    E: From<ExtractError>,
+    F: FnMut(T) -> Result<(), E>,
+{
+    let root = tree.root();
+
+    // a single kind of token gets an empty code, so there are no bits to walk
+    if let Some(token) = root.token_ref() {
+        if compressed_data.bit_len != 0 {
+            return Err(ExtractError::Corrupted(format!(
+                "{} bits of data for a single kind of token",
+                compressed_data.bit_len
+            ))
+            .into());
+        }
+        for _ in 0..token_count {
+            f(token.clone())?;
+        }
+        return Ok(());
+    }
 
-    // restore original token vector by walking on the huffman tree
     let data: BitVec<u8, Msb0> = BitVec::from_slice(&compressed_data.data);
-    let mut tokens = Vec::new();
-    let mut current_walk = &tree;
+    if compressed_data.bit_len > data.len() {
+        return Err(ExtractError::Truncated.into());
+    }
+
+    let mut current_walk = root;
+    let mut decoded: u64 = 0;
     for i in 0..compressed_data.bit_len {
-        if data[i] == false {
+        if !data[i] {
             current_walk = current_walk.left().unwrap();
         } else {
             current_walk = current_walk.right().unwrap();
         }
 
-        match current_walk {
-            HuffmanTree::Leaf {token, .. } => {
-                tokens.push(token.clone());
-                current_walk = &tree;
-            }
-            HuffmanTree::Node { .. } => {
-                // do nothing
-            }
+        if let Some(token) = current_walk.token_ref() {
+            f(token.clone())?;
+            decoded += 1;
+            current_walk = root;
         }
     }
 
-    tokens
+    if decoded != token_count {
+        return Err(ExtractError::Corrupted(format!(
+            "decoded {} tokens but expected {}",
+            decoded, token_count
+        ))
+        .into());
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -135,7 +493,113 @@ mod tests {
     fn test_hello_world() {
         let hello = input_to_hello();
         let compressed_data = compress(&hello);
-        let restored_data: Vec<u8> = extract(&compressed_data);
+        let restored_data: Vec<u8> = extract(&compressed_data).unwrap();
         assert_eq!(hello, restored_data);
     }
+
+    #[test]
+    fn test_compress_bytes_matches_generic() {
+        // skewed data with many distinct bytes, long enough to span several chunks
+        let data: Vec<u8> = (0..300_000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8 % ((i % 97) as u8 + 1))
+            .collect();
+        assert_eq!(compress_bytes(&data), compress(&data));
+
+        let hello = input_to_hello();
+        assert_eq!(compress_bytes(&hello), compress(&hello));
+    }
+
+    #[test]
+    fn test_extract_owned() {
+        let words: Vec<String> = "the cat saw the dog and the bird"
+            .split(' ')
+            .map(String::from)
+            .collect();
+        let compressed_data = compress(&words);
+
+        let restored_data: Vec<String> = extract_owned(&compressed_data).unwrap();
+        drop(compressed_data);
+        assert_eq!(words, restored_data);
+    }
+
+    #[test]
+    fn test_extract_from_reader() {
+        let hello = input_to_hello();
+        let compressed_data = compress(&hello);
+        let restored_data: Vec<u8> = extract_from_reader(compressed_data.as_slice()).unwrap();
+        assert_eq!(hello, restored_data);
+    }
+
+    #[test]
+    fn test_extracted_len() {
+        let hello = input_to_hello();
+        let compressed_data = compress(&hello);
+        assert_eq!(extracted_len::<u8>(&compressed_data).unwrap(), hello.len() as u64);
+    }
+
+    #[test]
+    fn test_single_kind_of_token() {
+        let data = vec![b'a'; 10];
+        let compressed_data = compress(&data);
+        assert_eq!(extract::<u8>(&compressed_data).unwrap(), data);
+    }
+
+    #[test]
+    fn test_empty() {
+        let compressed_data = compress_bytes(b"");
+        assert_eq!(compressed_data, compress::<u8>(b""));
+        assert!(extract::<u8>(&compressed_data).unwrap().is_empty());
+        assert_eq!(extracted_len::<u8>(&compressed_data).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_frequency_overflow() {
+        let compressed_data = CompressedData {
+            encoder: BTreeMap::from([(b'a', u64::MAX), (b'b', 1)]),
+            data: vec![0],
+            bit_len: 1,
+        };
+        let buf = rmp_serde::to_vec(&compressed_data).unwrap();
+        assert!(matches!(extract::<u8>(&buf), Err(ExtractError::Corrupted(_))));
+    }
+
+    #[test]
+    fn test_single_kind_of_token_with_data() {
+        let compressed_data = CompressedData {
+            encoder: BTreeMap::from([(b'a', 3)]),
+            data: vec![0xff],
+            bit_len: 8,
+        };
+        let buf = rmp_serde::to_vec(&compressed_data).unwrap();
+        assert!(matches!(extract::<u8>(&buf), Err(ExtractError::Corrupted(_))));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let hello = input_to_hello();
+        let compressed_data = compress(&hello);
+
+        for len in 0..compressed_data.len() - FOOTER_LEN {
+            let res = extract::<u8>(&compressed_data[..len]);
+            assert!(res.is_err(), "cut at {} bytes should be an error", len);
+        }
+    }
+
+    #[test]
+    fn test_lost_bytes_before_footer() {
+        let hello = input_to_hello();
+        let mut compressed_data = compress(&hello);
+        let at = compressed_data.len() - FOOTER_LEN - 3;
+        compressed_data.remove(at);
+        assert!(matches!(extract::<u8>(&compressed_data), Err(ExtractError::Truncated)));
+    }
+
+    #[test]
+    fn test_without_footer() {
+        // buffers written before the footer existed
+        let hello = input_to_hello();
+        let compressed_data = compress(&hello);
+        let body = &compressed_data[..compressed_data.len() - FOOTER_LEN];
+        assert_eq!(extract::<u8>(body).unwrap(), hello);
+    }
 }
@@ -0,0 +1,189 @@
+//! Downloading `http://` and `https://` sources, for builds with the `http` feature.
+//!
+//! A download that breaks off is resumed where it stopped with a `Range` request,
+//! so a flaky connection doesn't mean fetching a big file from the start again.
+//! Servers that ignore ranges send the whole file again, which is fine as well.
+//! A range that starts anywhere else than asked for is thrown away, and the file fetched from the start.
+
+use std::{
+    io::{self, Read},
+    time::Duration,
+};
+
+use ureq::Agent;
+
+/// How often a download is resumed in a row without getting any further.
+const MAX_RETRIES: u32 = 5;
+/// The wait before the first retry, doubled for each further one.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Limit for connecting and for the response headers; the body may take as long as it needs.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Download the body of `url` into memory.
+pub fn download(url: &str) -> io::Result<Vec<u8>> {
+    download_with_delay(url, RETRY_DELAY)
+}
+
+fn download_with_delay(url: &str, retry_delay: Duration) -> io::Result<Vec<u8>> {
+    let agent: Agent = Agent::config_builder()
+        .timeout_connect(Some(TIMEOUT))
+        .timeout_recv_response(Some(TIMEOUT))
+        .build()
+        .into();
+
+    let mut buf: Vec<u8> = Vec::new();
+    let mut retries = 0;
+    loop {
+        let start = buf.len();
+        let mut request = agent.get(url);
+        if start > 0 {
+            request = request.header("Range", format!("bytes={}-", start));
+        }
+
+        let err = match request.call() {
+            Ok(response) if response.status() == 206 => {
+                let resumed_at = response
+                    .headers()
+                    .get("Content-Range")
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(range_start);
+                if resumed_at == Some(start as u64) {
+                    match response.into_body().into_reader().read_to_end(&mut buf) {
+                        Ok(_) => return Ok(buf),
+                        Err(e) => e,
+                    }
+                } else {
+                    // appending this would corrupt the file; the next request goes without a range
+                    buf.clear();
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("the server sent a range other than the one asked for ({:?})", resumed_at),
+                    )
+                }
+            }
+            Ok(response) => {
+                // the server sends the whole file
+                buf.clear();
+                match response.into_body().into_reader().read_to_end(&mut buf) {
+                    Ok(_) => return Ok(buf),
+                    Err(e) => e,
+                }
+            }
+            // the request itself is wrong (404, 403, ...), asking again won't help
+            Err(ureq::Error::StatusCode(code)) if code < 500 => {
+                return Err(io::Error::other(format!("the server answered with status {}", code)));
+            }
+            Err(e) => e.into_io(),
+        };
+
+        if buf.len() > start {
+            retries = 0;
+        }
+        retries += 1;
+        if retries > MAX_RETRIES {
+            return Err(err);
+        }
+        eprintln!("ruf: download of '{}' interrupted ({}), resuming at byte {}", url, err, buf.len());
+        std::thread::sleep(retry_delay * 2u32.pow(retries - 1));
+    }
+}
+
+/// The first byte of a `Content-Range: bytes <first>-<last>/<total>` header.
+fn range_start(content_range: &str) -> Option<u64> {
+    let range = content_range.strip_prefix("bytes ")?;
+    range.split_once('-')?.0.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread::{self, JoinHandle},
+    };
+
+    /// Answer one connection after the other with `responses`, and return the requests' headers.
+    fn serve(responses: Vec<&'static str>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut request = String::new();
+                while reader.read_line(&mut request).unwrap() > 2 {}
+                (&stream).write_all(response.as_bytes()).unwrap();
+                requests.push(request.to_ascii_lowercase());
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    /// A response that breaks off halfway through the body.
+    const CUT: &str = "HTTP/1.1 200 OK\r\nContent-Length: 10\r\nConnection: close\r\n\r\nhello";
+
+    fn download(url: &str) -> io::Result<Vec<u8>> {
+        download_with_delay(url, Duration::from_millis(1))
+    }
+
+    #[test]
+    fn test_download_resumes() {
+        let (url, server) = serve(vec![
+            CUT,
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 5-9/10\r\nContent-Length: 5\r\nConnection: close\r\n\r\nworld",
+        ]);
+        assert_eq!(download(&url).unwrap(), b"helloworld");
+        let requests = server.join().unwrap();
+        assert!(!requests[0].contains("range:"));
+        assert!(requests[1].contains("range: bytes=5-"));
+    }
+
+    #[test]
+    fn test_download_without_ranges() {
+        // the server ignores the range and sends the whole file again
+        let (url, server) = serve(vec![
+            CUT,
+            "HTTP/1.1 200 OK\r\nContent-Length: 10\r\nConnection: close\r\n\r\nhelloworld",
+        ]);
+        assert_eq!(download(&url).unwrap(), b"helloworld");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_download_wrong_range() {
+        let (url, server) = serve(vec![
+            CUT,
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 3-9/10\r\nContent-Length: 7\r\nConnection: close\r\n\r\nloworld",
+            "HTTP/1.1 200 OK\r\nContent-Length: 10\r\nConnection: close\r\n\r\nhelloworld",
+        ]);
+        assert_eq!(download(&url).unwrap(), b"helloworld");
+        // the range is given up on after the bad answer
+        assert!(!server.join().unwrap()[2].contains("range:"));
+    }
+
+    #[test]
+    fn test_download_not_found() {
+        let (url, server) = serve(vec!["HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"]);
+        let err = download(&url).unwrap_err();
+        assert!(err.to_string().contains("404"));
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_download_gives_up() {
+        let (url, server) = serve(vec![CUT; MAX_RETRIES as usize + 1]);
+        assert!(download(&url).is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_range_start() {
+        assert_eq!(range_start("bytes 100-199/200"), Some(100));
+        assert_eq!(range_start("bytes 0-0/*"), Some(0));
+        assert_eq!(range_start("bytes */200"), None);
+        assert_eq!(range_start("items 1-2/3"), None);
+    }
+}
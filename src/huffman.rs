@@ -3,74 +3,151 @@ use std::cmp::Reverse;
 use std::collections::{BTreeMap, BinaryHeap};
 use std::hash::Hash;
 
-// PartialEq:
-// To build a huffman tree, we'll use a min heap,
-// which requires `Ord` trait.
-// the dependency of `Ord` is:
-//     `Ord` -> `PartialOrd` -> `Eq` -> `PartialEq`
-// the `T` as token should be `Eq` to impl `Ord`, `PartialOrd` and `Eq`
+/// A Huffman tree stored as an arena: all nodes live in one `Vec`,
+/// and internal nodes refer to their children by index.
+///
+/// This needs a single allocation for the whole tree instead of one `Box` per node,
+/// and keeps the nodes close together in memory when walking the tree.
 #[derive(PartialEq)]
-pub enum HuffmanTree<T> {
+pub struct HuffmanTree<T> {
+    nodes: Vec<Node<T>>,
+    root: u32,
+}
+
+#[derive(PartialEq)]
+enum Node<T> {
     // With `u64`, a token's frequency can be at most 2^64 - 1,
     // which should be enough for a directory of files of GiB size
     Leaf {
         frequency: u64,
         token: T,
     },
-    Node {
+    Internal {
         frequency: u64,
-        left: Box<HuffmanTree<T>>,
-        right: Box<HuffmanTree<T>>,
+        left: u32,
+        right: u32,
     },
 }
 
-#[allow(unused)]
-impl<T: Clone> HuffmanTree<T> {
-    pub fn frequency(&self) -> u64 {
+impl<T> Node<T> {
+    fn frequency(&self) -> u64 {
         match self {
             Self::Leaf { frequency, .. } => *frequency,
-            Self::Node { frequency, .. } => *frequency,
+            Self::Internal { frequency, .. } => *frequency,
         }
     }
+}
+
+/// A borrowed view of one node of a `HuffmanTree`.
+pub struct HuffmanNode<'a, T> {
+    tree: &'a HuffmanTree<T>,
+    index: u32,
+}
+
+// derived `Clone`/`Copy` would require `T: Clone`/`T: Copy`, which a reference doesn't need
+impl<T> Clone for HuffmanNode<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for HuffmanNode<'_, T> {}
+
+#[allow(unused)]
+impl<T: Clone> HuffmanTree<T> {
+    pub fn root(&self) -> HuffmanNode<'_, T> {
+        HuffmanNode { tree: self, index: self.root }
+    }
+
+    pub fn frequency(&self) -> u64 {
+        self.root().frequency()
+    }
 
     pub fn token(&self) -> Option<T> {
-        match self {
-            Self::Leaf { token, .. } => Some(token.clone()),
-            Self::Node { .. } => None,
+        self.root().token()
+    }
+
+    pub fn left(&self) -> Option<HuffmanNode<'_, T>> {
+        self.root().left()
+    }
+
+    pub fn right(&self) -> Option<HuffmanNode<'_, T>> {
+        self.root().right()
+    }
+}
+
+#[allow(unused)]
+impl<'a, T: Clone> HuffmanNode<'a, T> {
+    fn node(&self) -> &'a Node<T> {
+        &self.tree.nodes[self.index as usize]
+    }
+
+    fn at(&self, index: u32) -> Self {
+        HuffmanNode { tree: self.tree, index }
+    }
+
+    pub fn frequency(&self) -> u64 {
+        self.node().frequency()
+    }
+
+    pub fn token(&self) -> Option<T> {
+        self.token_ref().cloned()
+    }
+
+    /// The token of a leaf, without cloning it.
+    pub fn token_ref(&self) -> Option<&'a T> {
+        match self.node() {
+            Node::Leaf { token, .. } => Some(token),
+            Node::Internal { .. } => None,
         }
     }
 
-    pub fn left(&self) -> Option<&HuffmanTree<T>> {
-        match self {
-            Self::Leaf { .. } => None,
-            // deref coercion
-            Self::Node { left, .. } => Some(left),
+    pub fn left(&self) -> Option<Self> {
+        match self.node() {
+            Node::Leaf { .. } => None,
+            Node::Internal { left, .. } => Some(self.at(*left)),
         }
     }
 
-    pub fn right(&self) -> Option<&HuffmanTree<T>> {
-        match self {
-            Self::Leaf { .. } => None,
-            // deref coercion
-            Self::Node { right, .. } => Some(right),
+    pub fn right(&self) -> Option<Self> {
+        match self.node() {
+            Node::Leaf { .. } => None,
+            Node::Internal { right, .. } => Some(self.at(*right)),
         }
     }
 }
 
-impl<T: Clone + Eq> Ord for HuffmanTree<T> {
+// To build a huffman tree, we'll use a min heap,
+// which requires `Ord` trait.
+// the dependency of `Ord` is:
+//     `Ord` -> `PartialOrd` -> `Eq` -> `PartialEq`
+// Entries are ordered by frequency only, exactly like the whole subtrees used to be,
+// so ties are broken the same way and trees of existing `.ruf` files are rebuilt identically.
+struct HeapEntry {
+    frequency: u64,
+    index: u32,
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.frequency == other.frequency
+    }
+}
+
+impl Eq for HeapEntry {}
+
+impl Ord for HeapEntry {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.frequency().cmp(&other.frequency())
+        self.frequency.cmp(&other.frequency)
     }
 }
 
-impl<T: Clone + Eq> PartialOrd for HuffmanTree<T> {
+impl PartialOrd for HeapEntry {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl<T: Eq> Eq for HuffmanTree<T> {}
-
 /// This function takes in a BTreeMap of tokens -> frequency (taf, tokens and frequency)
 /// returns a Option of HuffmanTree
 ///
@@ -82,27 +159,37 @@ pub fn build_huffman_tree<T: Clone + Eq>(taf: &BTreeMap<T, u64>) -> Option<Huffm
         return None;
     }
 
-    let mut min_heap = BinaryHeap::new();
+    // a tree with n leaves has exactly n - 1 internal nodes
+    let mut nodes = Vec::with_capacity(2 * taf.len() - 1);
+    let mut min_heap = BinaryHeap::with_capacity(taf.len());
     for pair in taf {
-        let leaf = HuffmanTree::Leaf {
+        min_heap.push(Reverse(HeapEntry {
+            frequency: *pair.1,
+            index: nodes.len() as u32,
+        }));
+        nodes.push(Node::Leaf {
             frequency: *pair.1,
             token: pair.0.clone(),
-        };
-        min_heap.push(Reverse(leaf));
+        });
     }
 
     while min_heap.len() > 1 {
         let right = min_heap.pop().unwrap().0;
         let left = min_heap.pop().unwrap().0;
-        let node = HuffmanTree::Node {
-            frequency: left.frequency() + right.frequency(),
-            left: Box::new(left),
-            right: Box::new(right),
-        };
-        min_heap.push(Reverse(node));
+        let frequency = left.frequency + right.frequency;
+        min_heap.push(Reverse(HeapEntry {
+            frequency,
+            index: nodes.len() as u32,
+        }));
+        nodes.push(Node::Internal {
+            frequency,
+            left: left.index,
+            right: right.index,
+        });
     }
 
-    Some(min_heap.pop().unwrap().0)
+    let root = min_heap.pop().unwrap().0.index;
+    Some(HuffmanTree { nodes, root })
 }
 
 /// Generates the Huffman coding table from the given Huffman tree
@@ -119,12 +206,9 @@ pub fn get_coding_table<T: Clone + Ord + Hash>(
     let mut res = BTreeMap::new();
     let mut bv = bitvec![u8, Msb0;];
     
-    fn dfs_helper <T: Clone + Ord>(t: &HuffmanTree<T>, res: &mut BTreeMap<T, BitVec<u8, Msb0>>, bv: &mut BitVec<u8, Msb0>) {
-        match t {
-            HuffmanTree::Leaf {token , ..} => {
-                res.insert(token.clone(), bv.clone());
-            }
-            HuffmanTree::Node {left, right , ..} => {
+    fn dfs_helper <T: Clone + Ord>(t: HuffmanNode<'_, T>, res: &mut BTreeMap<T, BitVec<u8, Msb0>>, bv: &mut BitVec<u8, Msb0>) {
+        match (t.left(), t.right()) {
+            (Some(left), Some(right)) => {
                 bv.push(false);
                 dfs_helper(left, res, bv);
                 bv.pop();
@@ -132,13 +216,43 @@ pub fn get_coding_table<T: Clone + Ord + Hash>(
                 bv.push(true);
                 dfs_helper(right, res, bv);
                 bv.pop();
-            } 
+            }
+            _ => {
+                res.insert(t.token().unwrap(), bv.clone());
+            }
         }
     }
 
-    dfs_helper(huf_tree, &mut res, &mut bv);
+    dfs_helper(huf_tree.root(), &mut res, &mut bv);
 
-    return res;
+    res
+}
+
+/// The code of a token packed into an integer:
+/// the lowest `len` bits of `bits`, the first bit of the code being the most significant one.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct PackedCode {
+    pub bits: u64,
+    pub len: u8,
+}
+
+/// Generates the coding table of a tree of byte tokens as a flat array indexed by the byte.
+///
+/// Bytes that don't appear in the tree get an empty code.
+/// Returns `None` if some code is longer than 64 bits and cannot be packed,
+/// which takes a total frequency of at least tens of TiB.
+pub fn get_byte_coding_table(huf_tree: &HuffmanTree<u8>) -> Option<[PackedCode; 256]> {
+    let mut res = [PackedCode::default(); 256];
+    for (token, code) in get_coding_table(huf_tree) {
+        if code.len() > 64 {
+            return None;
+        }
+        res[token as usize] = PackedCode {
+            bits: code.iter().fold(0, |acc, bit| (acc << 1) | *bit as u64),
+            len: code.len() as u8,
+        };
+    }
+    Some(res)
 }
 
 #[cfg(test)]
@@ -153,23 +267,32 @@ mod tests {
         // a should be 0
         // b should be 10
         // c should be 11
-        let t = HuffmanTree::Node {
-            frequency: 55,
-            left: Box::new(HuffmanTree::Leaf {
-                token: String::from("a"),
-                frequency: 30,
-            }),
-            right: Box::new(HuffmanTree::Node {
-                frequency: 25,
-                left: Box::new(HuffmanTree::Leaf {
+        let t = HuffmanTree {
+            nodes: vec![
+                Node::Leaf {
+                    frequency: 30,
+                    token: String::from("a"),
+                },
+                Node::Leaf {
                     frequency: 15,
                     token: String::from("b"),
-                }),
-                right: Box::new(HuffmanTree::Leaf {
+                },
+                Node::Leaf {
                     frequency: 10,
                     token: String::from("c"),
-                }),
-            }),
+                },
+                Node::Internal {
+                    frequency: 25,
+                    left: 1,
+                    right: 2,
+                },
+                Node::Internal {
+                    frequency: 55,
+                    left: 0,
+                    right: 3,
+                },
+            ],
+            root: 4,
         };
 
         let map = get_coding_table(&t);
@@ -197,4 +320,21 @@ mod tests {
             "Token 'c' code mismatch"
         );
     }
+
+    #[test]
+    fn test_get_byte_coding_table() {
+        // same shape as above: a -> 0, b -> 10, c -> 11
+        let taf = BTreeMap::from([(b'a', 30), (b'b', 15), (b'c', 10)]);
+        let t = build_huffman_tree(&taf).unwrap();
+
+        let table = get_byte_coding_table(&t).unwrap();
+        let map = get_coding_table(&t);
+        for (token, code) in map {
+            let packed = table[token as usize];
+            assert_eq!(packed.len as usize, code.len());
+            let bits: Vec<bool> = (0..packed.len).rev().map(|i| (packed.bits >> i) & 1 == 1).collect();
+            assert_eq!(bits, code.iter().map(|b| *b).collect::<Vec<_>>());
+        }
+        assert_eq!(table[b'z' as usize], PackedCode::default());
+    }
 }
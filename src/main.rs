@@ -1,15 +1,43 @@
 use clap::{Parser, Subcommand};
+use stats::PhaseTimer;
 use std::{
-    fs::File, path::PathBuf,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
 };
 
+mod convert;
 mod core;
+#[cfg(feature = "http")]
+mod http;
 mod huffman;
+mod priority;
 mod service;
+mod stats;
+#[cfg(feature = "s3")]
+mod storage;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+mod uring;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Prompt before overwriting an existing dest file.
+    #[arg(short, long, global = true)]
+    interactive: bool,
+
+    /// Run at the lowest CPU and I/O priority, so the machine stays responsive.
+    #[arg(long, global = true)]
+    nice: bool,
+
+    /// Print the time spent in each phase and the peak memory usage when done.
+    #[arg(long, global = true)]
+    stats: bool,
+
+    /// How to read and write files; `uring` needs a Linux build with the `uring` feature.
+    #[arg(long, value_enum, global = true, default_value = "std")]
+    io: service::IoBackend,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -18,39 +46,360 @@ struct Args {
 enum Commands {
     /// compress a file
     Compress {
-        /// The source file that you want to compress.
+        /// The source file that you want to compress, or an http(s) URL to download it from.
         src: PathBuf,
         /// The dest file path to store compressed file
         dest: PathBuf,
     },
     /// extract a ruf-compressed file
     Extract {
-        /// The source file that you want to extract
+        /// The source file that you want to extract, or an http(s) URL to download it from
         src: PathBuf,
         /// The dest file path to store extracted file
         dest: PathBuf,
+        /// List the file that would be created and any problems with it, without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// search for a pattern inside ruf-compressed files, without extracting them
+    Grep {
+        /// The regular expression to look for
+        pattern: String,
+        /// Take `pattern` as literal text instead of a regular expression
+        #[arg(short = 'F', long)]
+        fixed_strings: bool,
+        /// The ruf-compressed files to search
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// convert between ruf-compressed files and tar/zip archives
+    Convert {
+        /// The format to convert into; `ruf` converts a tar or zip archive holding a single file
+        #[arg(long, value_enum)]
+        to: convert::Format,
+        /// The source file that you want to convert
+        src: PathBuf,
+        /// The dest file path to store converted file
+        dest: PathBuf,
+    },
+    /// print the SHA-256 of the content of ruf-compressed files, in `sha256sum` format
+    Sum {
+        /// The ruf-compressed files to checksum
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    }
+}
+
+/// Ask the user a yes/no question on the terminal, defaulting to "no".
+fn confirm(question: &str) -> bool {
+    eprint!("ruf: {} [y/N] ", question);
+    let _ = io::stderr().flush();
+
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes" | "Yes")
+}
+
+/// Whether `path` exists but is neither a regular file nor a directory,
+/// e.g. a named pipe or a character device like `/dev/null`.
+fn is_special_file(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|m| !m.file_type().is_file() && !m.file_type().is_dir())
+}
+
+/// Whether `a` and `b` are the same file, also through a symlink or a hard link.
+fn same_file(a: &Path, b: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        matches!((a.metadata(), b.metadata()), (Ok(a), Ok(b)) if a.dev() == b.dev() && a.ino() == b.ino())
+    }
+    #[cfg(not(unix))]
+    {
+        matches!((a.canonicalize(), b.canonicalize()), (Ok(a), Ok(b)) if a == b)
+    }
+}
+
+/// Create the dest file for the data read from `src`.
+///
+/// Without `interactive`, an existing dest file is an error as before.
+/// With it, the user is asked whether to overwrite it, and `None` is returned if they refuse.
+/// Overwriting `src` itself is always an error, as it would be truncated before it is read.
+///
+/// An existing special file (named pipe, device, ...) is opened for writing as is,
+/// so `ruf` can write into pipes and process substitutions.
+/// An object storage URL is checked the same way, but only written once the data is complete.
+fn create_dest(src: &Path, dest: &Path, interactive: bool) -> io::Result<Option<service::Dest>> {
+    if let Some(url) = service::object_url(dest) {
+        #[cfg(feature = "s3")]
+        {
+            if storage::exists(url)? {
+                if !interactive {
+                    return Err(io::Error::new(io::ErrorKind::AlreadyExists, "the object already exists"));
+                }
+                if !confirm(&format!("overwrite '{}'?", url)) {
+                    return Ok(None);
+                }
+            }
+            return Ok(Some(service::Dest::Object(url.to_string())));
+        }
+        #[cfg(not(feature = "s3"))]
+        return Err(service::needs_feature(url, "s3"));
+    }
+
+    let file = if is_special_file(dest) {
+        OpenOptions::new().write(true).open(dest)?
+    } else if interactive && dest.exists() {
+        if same_file(src, dest) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "it is the source file"));
+        }
+        if !confirm(&format!("overwrite '{}'?", dest.display())) {
+            return Ok(None);
+        }
+        File::create(dest)?
+    } else {
+        File::create_new(dest)?
+    };
+    Ok(Some(service::Dest::File(file)))
+}
+
+/// Report that `what` failed on `path` because of `e`, and exit.
+fn fail(what: &str, path: &Path, e: impl std::fmt::Display) -> ! {
+    eprintln!("ruf: cannot {} '{}': {}", what, path.display(), e);
+    std::process::exit(1);
+}
+
+/// Format a digest as lowercase hex, the way `sha256sum` prints it.
+fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Exit quietly if writing failed because the reader of a pipe went away,
+/// e.g. in `ruf extract a.ruf /dev/stdout | head`.
+fn exit_on_broken_pipe(e: &io::Error) {
+    if e.kind() == io::ErrorKind::BrokenPipe {
+        std::process::exit(0);
+    }
+}
+
+/// Print what extracting `src` into `dest` would do, without touching the disk.
+fn preview_extract(src: &service::Source, src_path: &Path, dest: &Path, interactive: bool, io: service::IoBackend) {
+    let size = service::extracted_size(src, io).unwrap_or_else(|e| fail("extract", src_path, e));
+    println!("{}\t{} bytes", dest.display(), size);
+
+    #[cfg(feature = "s3")]
+    if let Some(url) = service::object_url(dest) {
+        match storage::exists(url) {
+            Ok(false) => {}
+            Ok(true) if interactive => println!("  conflict: '{}' already exists, would ask before overwriting", url),
+            Ok(true) => println!("  conflict: '{}' already exists, extraction would fail", url),
+            Err(e) => println!("  error: cannot check '{}': {}", url, e),
+        }
+        return;
+    }
+
+    let parent = match dest.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    if dest.is_dir() {
+        println!("  error: '{}' is a directory", dest.display());
+    } else if is_special_file(dest) {
+        println!("  note: '{}' is not a regular file, would be written as a stream", dest.display());
+    } else if dest.exists() {
+        if interactive {
+            println!("  conflict: '{}' already exists, would ask before overwriting", dest.display());
+        } else {
+            println!("  conflict: '{}' already exists, extraction would fail", dest.display());
+        }
+    } else if !parent.is_dir() {
+        println!("  error: directory '{}' does not exist", parent.display());
     }
 }
 
 fn main() {
     let args = Args::parse();
 
+    #[cfg(feature = "trace")]
+    tracing_subscriber::fmt()
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .with_writer(io::stderr)
+        .init();
+
+    if args.nice {
+        if !priority::lower_current_thread() {
+            eprintln!("ruf: warning: cannot lower the priority, running at the normal one");
+        }
+        // the worker threads of `rayon` must be lowered one by one as well
+        rayon::ThreadPoolBuilder::new()
+            .start_handler(|_| {
+                priority::lower_current_thread();
+            })
+            .build_global()
+            .unwrap();
+    }
+
     match args.command {
         Commands::Compress { 
             src, 
             dest 
         } => {
-            let src_f = File::open(src).unwrap();
-            let mut dest_f = File::create_new(dest).unwrap();
-            service::compress_file(&src_f, &mut dest_f);
+            let src_f = service::Source::open(&src).unwrap_or_else(|e| fail("open", &src, e));
+            let Some(mut dest_f) = create_dest(&src, &dest, args.interactive).unwrap_or_else(|e| fail("create", &dest, e)) else {
+                return;
+            };
+            let mut timer = PhaseTimer::new();
+            if let Err(e) = service::compress_file(&src_f, &mut dest_f, args.io, &mut timer) {
+                exit_on_broken_pipe(&e);
+                // don't leave a half-written dest behind
+                if dest.is_file() {
+                    let _ = std::fs::remove_file(&dest);
+                }
+                fail("compress", &src, e);
+            }
+            if args.stats {
+                eprint!("{}", timer.report());
+            }
         },
         Commands::Extract { 
             src, 
-            dest 
+            dest,
+            dry_run,
+        } => {
+            let src_f = service::Source::open(&src).unwrap_or_else(|e| fail("open", &src, e));
+            if dry_run {
+                preview_extract(&src_f, &src, &dest, args.interactive, args.io);
+                return;
+            }
+            let Some(mut dest_f) = create_dest(&src, &dest, args.interactive).unwrap_or_else(|e| fail("create", &dest, e)) else {
+                return;
+            };
+            let mut timer = PhaseTimer::new();
+            if let Err(e) = service::extract_file(&src_f, &mut dest_f, args.io, &mut timer) {
+                if let core::ExtractError::Io(e) = &e {
+                    exit_on_broken_pipe(e);
+                }
+                // don't leave a half-written dest behind
+                if dest.is_file() {
+                    let _ = std::fs::remove_file(&dest);
+                }
+                fail("extract", &src, e);
+            }
+            if args.stats {
+                eprint!("{}", timer.report());
+            }
+        },
+        Commands::Grep {
+            pattern,
+            fixed_strings,
+            files,
+        } => {
+            let source = if fixed_strings { regex::escape(&pattern) } else { pattern };
+            let pattern = regex::bytes::Regex::new(&source).unwrap_or_else(|e| {
+                eprintln!("ruf: invalid pattern: {}", e);
+                std::process::exit(2);
+            });
+
+            let mut matches = 0;
+            let mut failed = false;
+            for file in files {
+                let src_f = match service::Source::open(&file) {
+                    Ok(src_f) => src_f,
+                    Err(e) => {
+                        eprintln!("ruf: cannot search '{}': {}", file.display(), e);
+                        failed = true;
+                        continue;
+                    }
+                };
+                let res = service::grep_file(&src_f, args.io, &pattern, |line_no, line| {
+                    println!("{}:{}:{}", file.display(), line_no, String::from_utf8_lossy(line));
+                });
+                match res {
+                    Ok(n) => matches += n,
+                    Err(e) => {
+                        eprintln!("ruf: cannot search '{}': {}", file.display(), e);
+                        failed = true;
+                    }
+                }
+            }
+            // follow `grep`: exit with 2 on errors, and with 1 when nothing matched
+            if failed {
+                std::process::exit(2);
+            }
+            if matches == 0 {
+                std::process::exit(1);
+            }
+        },
+        Commands::Convert {
+            to,
+            src,
+            dest,
         } => {
-            let src_f = File::open(src).unwrap();
-            let mut dest_f = File::create_new(dest).unwrap();
-            service::extract_file(&src_f, &mut dest_f);
+            let src_f = File::open(&src).unwrap_or_else(|e| fail("open", &src, e));
+            let Some(dest_f) = create_dest(&src, &dest, args.interactive).unwrap_or_else(|e| fail("create", &dest, e)) else {
+                return;
+            };
+            let mut dest_f = dest_f.into_file().unwrap_or_else(|e| fail("create", &dest, e));
+            if let Err(e) = convert::convert_file(&src_f, &src, to, &mut dest_f) {
+                exit_on_broken_pipe(&e);
+                // don't leave a half-written dest behind
+                if dest.is_file() {
+                    let _ = std::fs::remove_file(&dest);
+                }
+                fail("convert", &src, e);
+            }
+        },
+        Commands::Sum { files } => {
+            let mut failed = false;
+            for file in files {
+                // name the line after the extracted file, so `sha256sum -c` checks that one
+                if file.extension().is_none() {
+                    eprintln!(
+                        "ruf: cannot checksum '{}': it has no extension to strip, so the extracted file has no name",
+                        file.display()
+                    );
+                    failed = true;
+                    continue;
+                }
+                let src_f = match service::Source::open(&file) {
+                    Ok(src_f) => src_f,
+                    Err(e) => {
+                        eprintln!("ruf: cannot checksum '{}': {}", file.display(), e);
+                        failed = true;
+                        continue;
+                    }
+                };
+                match service::sha256_of_extracted(&src_f, args.io) {
+                    Ok(digest) => println!("{}  {}", to_hex(&digest), file.with_extension("").display()),
+                    Err(e) => {
+                        eprintln!("ruf: cannot checksum '{}': {}", file.display(), e);
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                std::process::exit(1);
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_dest_refuses_src() {
+        let path = std::env::temp_dir().join(format!("ruf-main-test-{}", std::process::id()));
+        std::fs::write(&path, b"precious").unwrap();
+
+        let res = create_dest(&path, &path, true);
+        let content = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(res.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+        assert_eq!(content, b"precious");
+    }
+}
@@ -0,0 +1,46 @@
+//! Lowering the CPU and I/O priority of the running thread, for `--nice`.
+//!
+//! On Linux both priorities are per thread, so this must be called on the main thread
+//! and on every worker thread (see `rayon::ThreadPoolBuilder::start_handler`).
+//! On other unix systems `setpriority` applies to the whole process and there is no I/O priority.
+
+/// The lowest CPU priority, as with `nice -n 19`.
+#[cfg(unix)]
+const LOWEST_NICE: libc::c_int = 19;
+
+/// Lower the priority of the calling thread as far as possible.
+///
+/// Returns `false` if the CPU priority could not be changed.
+/// The I/O priority is best effort and is silently left alone where unsupported.
+pub fn lower_current_thread() -> bool {
+    #[cfg(target_os = "linux")]
+    ionice_idle();
+
+    #[cfg(unix)]
+    {
+        // SAFETY: `setpriority` only reads its integer arguments.
+        unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, LOWEST_NICE) == 0 }
+    }
+
+    #[cfg(not(unix))]
+    false
+}
+
+/// Put the calling thread into the idle I/O scheduling class, as with `ionice -c 3`.
+#[cfg(target_os = "linux")]
+fn ionice_idle() {
+    // from `linux/ioprio.h`, which `libc` does not cover
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    // SAFETY: `ioprio_set` only reads its integer arguments.
+    unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0 as libc::c_int,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        );
+    }
+}
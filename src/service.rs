@@ -1,30 +1,260 @@
 use std::{
     fs::File,
-    io::{BufReader, Read, Write},
+    io::{self, BufReader, Read, Write},
+    path::Path,
 };
 
+use clap::ValueEnum;
+use regex::bytes::Regex;
+use sha2::{Digest, Sha256};
+
 use crate::core::*;
+use crate::stats::PhaseTimer;
+
+// This module contains all the service logic
+// of this `ruffman` program.
+
+/// How files are read and written.
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum IoBackend {
+    /// Buffered `std::io` calls
+    #[default]
+    Std,
+    /// Batched io_uring requests (Linux only)
+    #[cfg(all(target_os = "linux", feature = "uring"))]
+    Uring,
+}
+
+/// Where the data to compress, extract or search comes from.
+pub enum Source {
+    File(File),
+    /// An `http://` or `https://` URL, downloaded when it is read
+    #[cfg(feature = "http")]
+    Url(String),
+    /// An `s3://`, `gs://` or `az://` URL, downloaded when it is read
+    #[cfg(feature = "s3")]
+    Object(String),
+}
+
+impl Source {
+    /// Open `src`, which is either a local file or, with the `http` or `s3` feature, a URL.
+    pub fn open(src: &Path) -> io::Result<Source> {
+        if let Some(url) = object_url(src) {
+            #[cfg(feature = "s3")]
+            return Ok(Source::Object(url.to_string()));
+            #[cfg(not(feature = "s3"))]
+            return Err(needs_feature(url, "s3"));
+        }
+
+        let url = src.to_str().filter(|s| s.starts_with("http://") || s.starts_with("https://"));
+        match url {
+            #[cfg(feature = "http")]
+            Some(url) => Ok(Source::Url(url.to_string())),
+            #[cfg(not(feature = "http"))]
+            Some(url) => Err(needs_feature(url, "http")),
+            None => File::open(src).map(Source::File),
+        }
+    }
+}
+
+/// Where the compressed or extracted data goes.
+pub enum Dest {
+    File(File),
+    /// An `s3://`, `gs://` or `az://` URL, uploaded once the data is complete
+    #[cfg(feature = "s3")]
+    Object(String),
+}
 
-/// This module contains all the service logic
-/// of this `ruffman` program.
+impl Dest {
+    /// The dest as a local file, for the commands that can only write to one.
+    pub fn into_file(self) -> io::Result<File> {
+        match self {
+            Dest::File(file) => Ok(file),
+            #[cfg(feature = "s3")]
+            Dest::Object(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "this command cannot write to object storage",
+            )),
+        }
+    }
+}
+
+/// `path` as an `s3://`, `gs://` or `az://` URL, if it is one.
+pub fn object_url(path: &Path) -> Option<&str> {
+    path.to_str()
+        .filter(|s| ["s3://", "gs://", "az://"].iter().any(|scheme| s.starts_with(scheme)))
+}
+
+#[cfg(not(all(feature = "http", feature = "s3")))]
+pub fn needs_feature(url: &str, feature: &str) -> io::Error {
+    let scheme = url.split("://").next().unwrap_or(url);
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{}:// URLs need a build with the `{}` feature", scheme, feature),
+    )
+}
+
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+fn source_to_bytes(src: &Source, io: IoBackend) -> io::Result<Vec<u8>> {
+    match src {
+        Source::File(file) => file_to_bytes(file, io),
+        #[cfg(feature = "http")]
+        Source::Url(url) => crate::http::download(url),
+        #[cfg(feature = "s3")]
+        Source::Object(url) => crate::storage::download(url),
+    }
+}
+
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+fn file_to_bytes(file: &File, io: IoBackend) -> io::Result<Vec<u8>> {
+    match io {
+        IoBackend::Std => {
+            let mut reader = BufReader::new(file);
+            let mut res: Vec<u8> = Vec::new();
+            reader.read_to_end(&mut res)?;
+            Ok(res)
+        }
+        #[cfg(all(target_os = "linux", feature = "uring"))]
+        IoBackend::Uring => crate::uring::read_to_end(file),
+    }
+}
+
+fn bytes_to_file(dest: &mut File, buf: &[u8], io: IoBackend) -> io::Result<()> {
+    match io {
+        IoBackend::Std => dest.write_all(buf),
+        #[cfg(all(target_os = "linux", feature = "uring"))]
+        IoBackend::Uring => crate::uring::write_all(dest, buf),
+    }
+}
 
-fn file_to_bytes(file: &File) -> Vec<u8> {
-    let mut reader = BufReader::new(file);
-    let mut res: Vec<u8> = Vec::new();
-    let _ = reader.read_to_end(&mut res);
-    res
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+fn bytes_to_dest(dest: &mut Dest, buf: &[u8], io: IoBackend) -> io::Result<()> {
+    match dest {
+        Dest::File(file) => bytes_to_file(file, buf, io),
+        #[cfg(feature = "s3")]
+        Dest::Object(url) => crate::storage::upload(url, buf),
+    }
 }
 
-pub fn compress_file(src: &File, dest: &mut File) {
-    let tokens = file_to_bytes(src);
-    let buf = compress(&tokens);
-    let _ = dest.write(&buf);
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+pub fn compress_file(src: &Source, dest: &mut Dest, io: IoBackend, timer: &mut PhaseTimer) -> io::Result<()> {
+    let tokens = timer.time("reading", || source_to_bytes(src, io))?;
+    let buf = compress_bytes_timed(&tokens, timer);
+    timer.time("writing", || bytes_to_dest(dest, &buf, io))
 }
 
-pub fn extract_file(src: &File, dest: &mut File) {
-    let mut reader = BufReader::new(src);
-    let mut buf = Vec::new();
-    let _ = reader.read_to_end(&mut buf);
-    let data: Vec<u8> = extract(&buf);
-    let _ = dest.write(&data);
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+pub fn extract_file(src: &Source, dest: &mut Dest, io: IoBackend, timer: &mut PhaseTimer) -> Result<(), ExtractError> {
+    let buf = timer.time("reading", || source_to_bytes(src, io)).map_err(ExtractError::Io)?;
+    let mut data: Vec<u8> = Vec::new();
+    extract_each_timed(&buf, |token| data.push(token), timer)?;
+    timer.time("writing", || bytes_to_dest(dest, &data, io)).map_err(ExtractError::Io)
+}
+
+/// Size in bytes of the file that `extract_file` would write for `src`.
+pub fn extracted_size(src: &Source, io: IoBackend) -> Result<u64, ExtractError> {
+    let buf = source_to_bytes(src, io).map_err(ExtractError::Io)?;
+    extracted_len::<u8>(&buf)
+}
+
+/// Search the extracted content of `src` for lines matching `pattern`.
+///
+/// The content is decoded and scanned line by line, and is never written to disk.
+/// `on_match` is called with the 1-based line number and the line (without its `\n`)
+/// for every matching line. Returns the number of matching lines.
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+pub fn grep_file<F>(src: &Source, io: IoBackend, pattern: &Regex, mut on_match: F) -> Result<usize, ExtractError>
+where
+    F: FnMut(usize, &[u8]),
+{
+    let buf = source_to_bytes(src, io).map_err(ExtractError::Io)?;
+    let mut line: Vec<u8> = Vec::new();
+    let mut line_no = 0;
+    let mut matches = 0;
+
+    let mut check_line = |line: &[u8], line_no: usize| {
+        if pattern.is_match(line) {
+            on_match(line_no, line);
+            matches += 1;
+        }
+    };
+
+    extract_each::<u8, _>(&buf, |byte| {
+        if byte == b'\n' {
+            line_no += 1;
+            check_line(&line, line_no);
+            line.clear();
+        } else {
+            line.push(byte);
+        }
+    })?;
+    // the last line may not end with `\n`
+    if !line.is_empty() {
+        check_line(&line, line_no + 1);
+    }
+
+    Ok(matches)
+}
+
+/// SHA-256 digest of the extracted content of `src`, computed while decoding.
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+pub fn sha256_of_extracted(src: &Source, io: IoBackend) -> Result<[u8; 32], ExtractError> {
+    let buf = source_to_bytes(src, io).map_err(ExtractError::Io)?;
+    let mut hasher = Sha256::new();
+    let mut chunk: Vec<u8> = Vec::with_capacity(CHUNK_SIZE);
+
+    extract_each::<u8, _>(&buf, |byte| {
+        chunk.push(byte);
+        if chunk.len() == CHUNK_SIZE {
+            hasher.update(&chunk);
+            chunk.clear();
+        }
+    })?;
+    hasher.update(&chunk);
+
+    Ok(hasher.finalize().into())
+}
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compressed_source(name: &str, content: &[u8]) -> (Source, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("ruf-service-test-{}-{}", name, std::process::id()));
+        std::fs::write(&path, compress_bytes(content)).unwrap();
+        (Source::File(File::open(&path).unwrap()), path)
+    }
+
+    #[test]
+    fn test_grep_file() {
+        let (src, path) = compressed_source("grep", b"apple pie\nbanana\n\npineapple");
+        let pattern = Regex::new("apple").unwrap();
+
+        let mut found: Vec<(usize, Vec<u8>)> = Vec::new();
+        let matches = grep_file(&src, IoBackend::Std, &pattern, |line_no, line| found.push((line_no, line.to_vec())));
+        std::fs::remove_file(&path).unwrap();
+
+        // the last line has no `\n`, and the empty line still counts
+        assert_eq!(matches.unwrap(), 2);
+        assert_eq!(found, [(1, b"apple pie".to_vec()), (4, b"pineapple".to_vec())]);
+    }
+
+    #[test]
+    fn test_read_error() {
+        // a directory opens fine, but can't be read
+        let src = Source::File(File::open(std::env::temp_dir()).unwrap());
+        assert!(matches!(sha256_of_extracted(&src, IoBackend::Std), Err(ExtractError::Io(_))));
+    }
+
+    #[test]
+    fn test_sha256_of_extracted() {
+        let (src, path) = compressed_source("sha256", b"abc");
+        let digest = sha256_of_extracted(&src, IoBackend::Std);
+        std::fs::remove_file(&path).unwrap();
+
+        let hex: String = digest.unwrap().iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hex, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
 }
@@ -0,0 +1,86 @@
+//! Performance counters: time spent in each phase of a run, and the peak memory usage.
+
+use std::time::{Duration, Instant};
+
+/// Records how long each phase of a run takes, in the order they happen.
+/// A phase timed more than once accumulates.
+///
+/// With the `trace` feature, every phase is also entered as a `tracing` span.
+#[derive(Default)]
+pub struct PhaseTimer {
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl PhaseTimer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `f`, adding the time it takes to the phase `name`.
+    pub fn time<R>(&mut self, name: &'static str, f: impl FnOnce() -> R) -> R {
+        #[cfg(feature = "trace")]
+        let _span = tracing::info_span!("phase", name).entered();
+
+        let start = Instant::now();
+        let res = f();
+        let elapsed = start.elapsed();
+
+        match self.phases.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, d)) => *d += elapsed,
+            None => self.phases.push((name, elapsed)),
+        }
+        res
+    }
+
+    /// A human readable table of the phases, their share of the total, and the peak memory.
+    pub fn report(&self) -> String {
+        let total: Duration = self.phases.iter().map(|(_, d)| *d).sum();
+        let mut res = String::new();
+
+        for (name, d) in &self.phases {
+            let share = if total.is_zero() {
+                0.0
+            } else {
+                d.as_secs_f64() / total.as_secs_f64() * 100.0
+            };
+            res += &format!("{:<16}{:>12.3} ms{:>8.1}%\n", name, ms(*d), share);
+        }
+        res += &format!("{:<16}{:>12.3} ms\n", "total", ms(total));
+
+        match peak_memory() {
+            Some(bytes) => res += &format!("{:<16}{:>12.1} MiB\n", "peak memory", bytes as f64 / (1024.0 * 1024.0)),
+            None => res += &format!("{:<16}{:>12}\n", "peak memory", "unknown"),
+        }
+        res
+    }
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+/// Peak resident memory of this process in bytes, where the platform tells us.
+pub fn peak_memory() -> Option<u64> {
+    // `VmHWM` ("high water mark") is the peak resident set size, in kB
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phases_accumulate_in_order() {
+        let mut timer = PhaseTimer::new();
+        let x = timer.time("a", || 1);
+        timer.time("b", || ());
+        timer.time("a", || ());
+
+        assert_eq!(x, 1);
+        let names: Vec<_> = timer.phases.iter().map(|(n, _)| *n).collect();
+        assert_eq!(names, ["a", "b"]);
+    }
+}
@@ -0,0 +1,121 @@
+//! Reading and writing objects in cloud storage (`s3://`, `gs://` and `az://` URLs),
+//! for builds with the `s3` feature.
+//!
+//! The stores are reached through the `object_store` crate. Credentials and settings come from
+//! the usual environment variables of each cloud (`AWS_ACCESS_KEY_ID`, `AWS_REGION`, `AWS_ENDPOINT`,
+//! `GOOGLE_SERVICE_ACCOUNT`, `AZURE_STORAGE_ACCOUNT_NAME`, ...).
+//!
+//! Objects are written as multipart uploads of `PART_SIZE` blocks, a few of them in flight at once,
+//! so a compressed file goes straight from memory into the bucket without a local copy.
+
+use std::{future::Future, io};
+
+use object_store::{ObjectStore, ObjectStoreExt, PutPayload, parse_url_opts, path::Path};
+use tokio::task::JoinSet;
+use url::Url;
+
+/// The size of each part of a multipart upload. S3 needs at least 5 MiB for all parts but the last.
+const PART_SIZE: usize = 8 << 20;
+/// How many parts are uploaded at the same time.
+const MAX_PARTS_IN_FLIGHT: usize = 4;
+
+/// Download the object at `url` into memory.
+pub fn download(url: &str) -> io::Result<Vec<u8>> {
+    let (store, path) = open(url)?;
+    block_on(get_all(store.as_ref(), &path))
+}
+
+/// Whether there is an object at `url`.
+pub fn exists(url: &str) -> io::Result<bool> {
+    let (store, path) = open(url)?;
+    block_on(async {
+        match store.head(&path).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::NotFound { .. }) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    })
+}
+
+/// Store `buf` as the object at `url`, replacing any object already there.
+pub fn upload(url: &str, buf: &[u8]) -> io::Result<()> {
+    let (store, path) = open(url)?;
+    block_on(put_all(store.as_ref(), &path, buf, PART_SIZE))
+}
+
+fn open(url: &str) -> io::Result<(Box<dyn ObjectStore>, Path)> {
+    let url = Url::parse(url).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // the builders pick the keys they know (`aws_region`, ...) out of the environment
+    let vars = std::env::vars().map(|(k, v)| (k.to_ascii_lowercase(), v));
+    parse_url_opts(&url, vars).map_err(io::Error::from)
+}
+
+/// Run `f` to completion on a runtime of its own; `ruf` is not async anywhere else.
+fn block_on<F: Future<Output = io::Result<T>>, T>(f: F) -> io::Result<T> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(f)
+}
+
+async fn get_all(store: &dyn ObjectStore, path: &Path) -> io::Result<Vec<u8>> {
+    let bytes = store.get(path).await?.bytes().await?;
+    Ok(bytes.to_vec())
+}
+
+async fn put_all(store: &dyn ObjectStore, path: &Path, buf: &[u8], part_size: usize) -> io::Result<()> {
+    // a multipart upload needs at least one part, and a single request is cheaper anyway
+    if buf.len() <= part_size {
+        store.put(path, PutPayload::from(buf.to_vec())).await?;
+        return Ok(());
+    }
+
+    let mut upload = store.put_multipart(path).await?;
+    let mut parts = JoinSet::new();
+    let res: io::Result<()> = async {
+        for chunk in buf.chunks(part_size) {
+            if parts.len() >= MAX_PARTS_IN_FLIGHT {
+                parts.join_next().await.expect("a part is in flight").map_err(io::Error::other)??;
+            }
+            parts.spawn(upload.put_part(PutPayload::from(chunk.to_vec())));
+        }
+        while let Some(part) = parts.join_next().await {
+            part.map_err(io::Error::other)??;
+        }
+        upload.complete().await?;
+        Ok(())
+    }
+    .await;
+
+    if res.is_err() {
+        // don't leave the uploaded parts behind to be billed for; this is best effort
+        parts.abort_all();
+        let _ = upload.abort().await;
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    #[test]
+    fn test_round_trip() {
+        let store = InMemory::new();
+        let path = Path::from("backups/data.ruf");
+        // several parts and a partial one
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+
+        block_on(async {
+            put_all(&store, &path, &data, 4096).await?;
+            assert_eq!(get_all(&store, &path).await?, data);
+
+            // a small object goes up in one request
+            put_all(&store, &path, b"small", 4096).await?;
+            assert_eq!(get_all(&store, &path).await?, b"small");
+            Ok(())
+        })
+        .unwrap();
+    }
+}
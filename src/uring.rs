@@ -0,0 +1,244 @@
+//! An io_uring based backend for reading and writing whole files, selected with `--io uring`.
+//!
+//! A file is transferred as chunks of `CHUNK_SIZE` bytes at explicit offsets,
+//! with up to `QUEUE_DEPTH` of them in flight at once, so the device sees a deep queue
+//! instead of one synchronous request after the other.
+//! The memory of the transfer is registered with the kernel once (fixed buffers),
+//! which saves mapping the pages again for every request.
+//!
+//! Files without offsets (pipes, devices, ...) are handled by the usual `std::io` calls,
+//! and so is everything on kernels where io_uring is missing or turned off.
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::fd::AsRawFd,
+};
+
+use io_uring::{opcode, squeue::Entry, types, IoUring};
+
+const QUEUE_DEPTH: usize = 32;
+const CHUNK_SIZE: usize = 1 << 20;
+/// The kernel caps every registered buffer at 1 GiB, so larger transfers register several.
+/// This is a multiple of `CHUNK_SIZE`, so no chunk straddles two registered buffers.
+const REGISTERED_SIZE: usize = 1 << 30;
+
+/// Read `file` from its current position to its end.
+pub fn read_to_end(file: &File) -> io::Result<Vec<u8>> {
+    let mut file = file;
+    let metadata = file.metadata()?;
+    let ring = match new_ring()? {
+        Some(ring) if metadata.is_file() => ring,
+        _ => {
+            let mut buf = Vec::new();
+            file.read_to_end(&mut buf)?;
+            return Ok(buf);
+        }
+    };
+
+    let start = file.stream_position()?;
+    let mut buf = vec![0u8; metadata.len().saturating_sub(start) as usize];
+    let len = buf.len();
+    let read = transfer(ring, file, start, buf.as_mut_ptr(), len, Direction::Read)?;
+    buf.truncate(read);
+
+    // pick up anything appended to the file in the meantime
+    file.seek(SeekFrom::Start(start + read as u64))?;
+    file.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Write all of `buf` into `file` at its current position.
+pub fn write_all(file: &File, buf: &[u8]) -> io::Result<()> {
+    let mut file = file;
+    let ring = match new_ring()? {
+        Some(ring) if file.metadata()?.is_file() => ring,
+        _ => return file.write_all(buf),
+    };
+
+    let start = file.stream_position()?;
+    // a write request only reads from the buffer, the `*mut` is just what `transfer` shares with reads
+    transfer(ring, file, start, buf.as_ptr() as *mut u8, buf.len(), Direction::Write)?;
+    file.seek(SeekFrom::Start(start + buf.len() as u64))?;
+    Ok(())
+}
+
+/// A ring for one transfer, or `None` if the kernel doesn't offer io_uring to us.
+fn new_ring() -> io::Result<Option<IoUring>> {
+    match IoUring::new(QUEUE_DEPTH as u32) {
+        Ok(ring) => Ok(Some(ring)),
+        // ENOSYS on old kernels, EPERM when disabled by sysctl or seccomp
+        Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EPERM | libc::EOPNOTSUPP)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    Read,
+    Write,
+}
+
+/// Transfer the `len` bytes at `base` from or to `file`, starting at the file offset `start`.
+///
+/// Returns the number of bytes transferred, which is less than `len` only when reading hits the end of the file.
+/// On an error, no request is left in flight: the kernel is done with the buffer either way.
+fn transfer(
+    mut ring: IoUring,
+    file: &File,
+    start: u64,
+    base: *mut u8,
+    len: usize,
+    direction: Direction,
+) -> io::Result<usize> {
+    if len == 0 {
+        return Ok(0);
+    }
+
+    let fd = types::Fd(file.as_raw_fd());
+
+    let iovecs: Vec<libc::iovec> = (0..len)
+        .step_by(REGISTERED_SIZE)
+        .map(|pos| libc::iovec {
+            // SAFETY: `pos < len`, so this stays inside the buffer
+            iov_base: unsafe { base.add(pos) }.cast(),
+            iov_len: REGISTERED_SIZE.min(len - pos),
+        })
+        .collect();
+    // SAFETY: the buffers stay alive and unmoved until `ring` is dropped at the end of this function.
+    // Registration can be refused (e.g. by the locked memory limit), then plain requests are used.
+    let registered = unsafe { ring.submitter().register_buffers(&iovecs) }.is_ok();
+
+    let make_entry = |pos: usize, n: usize| -> Entry {
+        // SAFETY: callers only pass ranges inside the buffer
+        let ptr = unsafe { base.add(pos) };
+        let offset = start + pos as u64;
+        let buf_index = (pos / REGISTERED_SIZE) as u16;
+        match (direction, registered) {
+            (Direction::Read, true) => opcode::ReadFixed::new(fd, ptr, n as u32, buf_index).offset(offset).build(),
+            (Direction::Read, false) => opcode::Read::new(fd, ptr, n as u32).offset(offset).build(),
+            (Direction::Write, true) => opcode::WriteFixed::new(fd, ptr, n as u32, buf_index).offset(offset).build(),
+            (Direction::Write, false) => opcode::Write::new(fd, ptr, n as u32).offset(offset).build(),
+        }
+    };
+
+    // requests in flight, indexed by their `user_data`
+    let mut slots: [Option<(usize, usize)>; QUEUE_DEPTH] = [None; QUEUE_DEPTH];
+    // the rest of short reads/writes, to submit again
+    let mut retries: Vec<(usize, usize)> = Vec::new();
+    let mut next = 0;
+    let mut done = 0;
+    // the end of the file, if a read found it
+    let mut eof = len;
+    // the first error; once there is one, the requests in flight are only waited for
+    let mut failed: Option<io::Error> = None;
+
+    loop {
+        // fill the free slots with new requests
+        for (i, slot) in slots.iter_mut().enumerate() {
+            if failed.is_some() {
+                break;
+            }
+            if slot.is_some() {
+                continue;
+            }
+            let request = match retries.pop() {
+                Some(request) => request,
+                None if next < eof => {
+                    let n = CHUNK_SIZE.min(eof - next);
+                    next += n;
+                    (next - n, n)
+                }
+                None => break,
+            };
+            let entry = make_entry(request.0, request.1).user_data(i as u64);
+            // SAFETY: the entry points into the buffer, which outlives the ring
+            unsafe { ring.submission().push(&entry) }.expect("the queue has a free entry per free slot");
+            *slot = Some(request);
+        }
+
+        let in_flight = slots.iter().filter(|s| s.is_some()).count();
+        if in_flight == 0 {
+            break;
+        }
+        if let Err(e) = ring.submit_and_wait(1) {
+            match e.kind() {
+                // interrupted by a signal, or the completion queue is full: reap what is there and wait again
+                io::ErrorKind::Interrupted | io::ErrorKind::ResourceBusy | io::ErrorKind::WouldBlock => {}
+                // returning would free the buffer under requests the kernel may still be working on
+                _ => {
+                    eprintln!("ruf: cannot wait for io_uring requests in flight: {}", e);
+                    std::process::abort();
+                }
+            }
+        }
+
+        let completed: Vec<(u64, i32)> = ring.completion().map(|c| (c.user_data(), c.result())).collect();
+        for (i, res) in completed {
+            let (pos, n) = slots[i as usize].take().unwrap();
+            if res < 0 {
+                let err = io::Error::from_raw_os_error(-res);
+                if err.kind() == io::ErrorKind::Interrupted || err.kind() == io::ErrorKind::WouldBlock {
+                    retries.push((pos, n));
+                    continue;
+                }
+                failed.get_or_insert(err);
+                continue;
+            }
+
+            let res = res as usize;
+            if res == 0 {
+                match direction {
+                    // the file is shorter than it was: nothing to read from here on
+                    Direction::Read => eof = eof.min(pos),
+                    Direction::Write => {
+                        failed.get_or_insert(io::ErrorKind::WriteZero.into());
+                    }
+                }
+            } else if res < n {
+                retries.push((pos + res, n - res));
+            }
+            done += res;
+        }
+        retries.retain(|&(pos, _)| pos < eof);
+    }
+
+    match failed {
+        Some(err) => Err(err),
+        None => Ok(done.min(eof)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let path = std::env::temp_dir().join(format!("ruf-uring-test-{}", std::process::id()));
+        // several chunks and a partial one
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE + 12345).map(|i| (i % 251) as u8).collect();
+
+        let file = File::create(&path).unwrap();
+        write_all(&file, &data).unwrap();
+        drop(file);
+
+        let file = File::open(&path).unwrap();
+        let restored = read_to_end(&file).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restored, data);
+    }
+
+    #[test]
+    fn test_write_error() {
+        let path = std::env::temp_dir().join(format!("ruf-uring-error-test-{}", std::process::id()));
+        File::create(&path).unwrap();
+
+        // every request of a write into a read-only file fails, after many were submitted
+        let file = File::open(&path).unwrap();
+        let data = vec![0u8; 3 * CHUNK_SIZE];
+        let res = write_all(&file, &data);
+        std::fs::remove_file(&path).unwrap();
+        assert!(res.is_err());
+    }
+}